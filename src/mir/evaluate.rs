@@ -1,12 +1,26 @@
 //! Evaluate any compile-time function applications in the Hir to remove handler abstractions
+use std::{ cell::Cell, rc::Rc };
+
 use crate::util::fmap;
 
 use super::ir::{ self as mir, Ast, dispatch_on_mir, DefinitionId, Atom, Mir };
 
+/// Default number of compile_time calls `evaluate_static_calls` will inline, per top-level
+/// function, before giving up on further inlining. See `evaluate_static_calls_with_fuel`.
+const DEFAULT_EVALUATION_FUEL: usize = 10_000;
+
 impl Mir {
-    pub fn evaluate_static_calls(mut self) -> Mir {
+    pub fn evaluate_static_calls(self) -> Mir {
+        self.evaluate_static_calls_with_fuel(DEFAULT_EVALUATION_FUEL)
+    }
+
+    /// Like `evaluate_static_calls`, but with a configurable limit on how many compile_time
+    /// calls will be inlined per top-level function. Without such a limit, a recursive
+    /// `compile_time` function (or a mutually recursive pair) would inline forever and hang
+    /// the compiler with no diagnostic.
+    pub fn evaluate_static_calls_with_fuel(mut self, fuel: usize) -> Mir {
         self.functions = self.functions.into_iter().map(|(id, function)| {
-            (id, function.evaluate(&im::HashMap::new()))
+            (id, function.evaluate(&Context::new(fuel)))
         }).collect();
         self
     }
@@ -14,39 +28,92 @@ impl Mir {
 
 type Substitutions = im::HashMap<DefinitionId, Atom>;
 
-/// Evaluate static calls in `self` using the given substitutions
+/// The substitutions active at a given point of the evaluation pass, plus a fuel budget shared
+/// across the whole evaluation of a single top-level function. Cloning a `Context` (e.g. to
+/// shadow variables while descending into a `mir::Lambda`) clones the substitutions but shares
+/// the same fuel counter, so inlining is bounded across the entire call, not just one branch of it.
+#[derive(Clone)]
+struct Context {
+    substitutions: Substitutions,
+    fuel: Rc<Cell<usize>>,
+    /// The fuel budget `fuel` started out with, kept around so the "out of fuel" diagnostic can
+    /// report the limit that was actually configured instead of the remaining fuel (always 0 by
+    /// the time that diagnostic fires).
+    total_fuel: usize,
+    /// Set once the "out of fuel" warning has been printed for this top-level function. A
+    /// residual, un-inlined `FunctionCall` stays embedded in the Ast it was returned into, so
+    /// every ancestor call's own second `.evaluate` pass over its (already-reduced) result visits
+    /// that same residual again; without this guard each of those revisits would reprint the
+    /// warning, turning one diagnostic into one per stack frame unwound.
+    warned_out_of_fuel: Rc<Cell<bool>>,
+}
+
+impl Context {
+    fn new(fuel: usize) -> Context {
+        Context {
+            substitutions: im::HashMap::new(),
+            fuel: Rc::new(Cell::new(fuel)),
+            total_fuel: fuel,
+            warned_out_of_fuel: Rc::new(Cell::new(false)),
+        }
+    }
+
+    fn with_substitutions(&self, substitutions: Substitutions) -> Context {
+        Context {
+            substitutions,
+            fuel: self.fuel.clone(),
+            total_fuel: self.total_fuel,
+            warned_out_of_fuel: self.warned_out_of_fuel.clone(),
+        }
+    }
+
+    /// Consume one unit of fuel, returning `false` without consuming any if none remains.
+    fn consume_fuel(&self) -> bool {
+        let remaining = self.fuel.get();
+        let has_fuel = remaining != 0;
+        if has_fuel {
+            self.fuel.set(remaining - 1);
+        }
+        has_fuel
+    }
+}
+
+/// Evaluate static calls in `self` using the given context
 trait Evaluate<T> {
-    fn evaluate(self, substitutions: &Substitutions) -> T;
+    fn evaluate(self, context: &Context) -> T;
 }
 
 impl Evaluate<Ast> for Ast {
-    fn evaluate(self, substitutions: &Substitutions) -> Ast {
-        dispatch_on_mir!(self, Evaluate::evaluate, substitutions)
+    fn evaluate(self, context: &Context) -> Ast {
+        dispatch_on_mir!(self, Evaluate::evaluate, context)
     }
 }
 
 impl Evaluate<Atom> for Atom {
-    fn evaluate(self, substitutions: &Substitutions) -> Atom {
-        dispatch_on_atom!(self, Evaluate::evaluate, substitutions)
+    fn evaluate(self, context: &Context) -> Atom {
+        dispatch_on_atom!(self, Evaluate::evaluate, context)
     }
 }
 
 impl Evaluate<Ast> for Atom {
-    fn evaluate(self, substitutions: &Substitutions) -> Ast {
-        Ast::Atom(self.evaluate(substitutions))
+    fn evaluate(self, context: &Context) -> Ast {
+        Ast::Atom(self.evaluate(context))
     }
 }
 
 impl Evaluate<Atom> for mir::Literal {
-    fn evaluate(self, _: &Substitutions) -> Atom {
+    fn evaluate(self, _: &Context) -> Atom {
         Atom::Literal(self)
     }
 }
 
 impl Evaluate<Atom> for mir::Variable {
-    fn evaluate(self, substitutions: &Substitutions) -> Atom {
-        match substitutions.get(&self.definition_id) {
-            Some(ast) => ast.clone(), // Should we recur here?
+    // Substituted atoms are re-evaluated under the current substitutions rather than returned
+    // as-is, so chains of substitutions (e.g. `x := y` followed later by `y := 3`) fully
+    // collapse instead of leaving `x` as a dangling reference to `y`.
+    fn evaluate(self, context: &Context) -> Atom {
+        match context.substitutions.get(&self.definition_id) {
+            Some(atom) => atom.clone().evaluate(context),
             None => Atom::Variable(self),
         }
     }
@@ -54,40 +121,323 @@ impl Evaluate<Atom> for mir::Variable {
 
 impl Evaluate<Atom> for mir::Lambda {
     // Any variables introduced by the lambda shadow any matching variables in `substitutions`,
-    // so make sure to remove them before evaluating the lambda body.
-    fn evaluate(mut self, substitutions: &Substitutions) -> Atom {
-        let mut substitutions = substitutions.clone();
+    // so make sure to remove them before evaluating the lambda body. This alone isn't enough to
+    // prevent variable capture though: if a pending substitution's value has one of this
+    // lambda's args free (e.g. it substitutes some outer variable with `arg`, or with a lambda
+    // that closes over `arg`), evaluating that substitution under this lambda's body would
+    // capture it. Alpha-rename the arg to a fresh id first in that case.
+    fn evaluate(mut self, context: &Context) -> Atom {
+        let mut substitutions = context.substitutions.clone();
+
+        for arg in &mut self.args {
+            if substitutions.values().any(|value| free_definition_ids(value).contains(&arg.definition_id)) {
+                let fresh_id = fresh_definition_id();
+                *self.body = rename_in_ast(*self.body, arg.definition_id, fresh_id);
+                arg.definition_id = fresh_id;
+            }
 
-        for arg in &self.args {
             substitutions.remove(&arg.definition_id);
         }
 
-        *self.body = self.body.evaluate(&substitutions);
+        let context = context.with_substitutions(substitutions);
+        *self.body = self.body.evaluate(&context);
         Atom::Lambda(self)
     }
 }
 
+/// A `DefinitionId` guaranteed not to collide with any id already in the program, for
+/// alpha-renaming a lambda argument during capture-avoiding substitution.
+fn fresh_definition_id() -> DefinitionId {
+    use std::sync::atomic::{ AtomicU32, Ordering };
+
+    // Counts down from the top of the id space so these renamed ids can never collide with the
+    // ones assigned to real source bindings, which are allocated from zero upward.
+    static NEXT_ALPHA_RENAME_ID: AtomicU32 = AtomicU32::new(u32::MAX);
+    DefinitionId(NEXT_ALPHA_RENAME_ID.fetch_sub(1, Ordering::Relaxed))
+}
+
+/// The set of `DefinitionId`s referenced by `atom` that are not bound within `atom` itself.
+fn free_definition_ids(atom: &Atom) -> im::HashSet<DefinitionId> {
+    match atom {
+        Atom::Literal(_) | Atom::Extern(_) | Atom::Effect(_) => im::HashSet::new(),
+        Atom::Variable(variable) => im::HashSet::unit(variable.definition_id),
+        Atom::Lambda(lambda) => {
+            let mut free = free_definition_ids_in_ast(&lambda.body);
+            for arg in &lambda.args {
+                free.remove(&arg.definition_id);
+            }
+            free
+        },
+    }
+}
+
+/// The set of `DefinitionId`s referenced by `ast` that are not bound within `ast` itself.
+fn free_definition_ids_in_ast(ast: &Ast) -> im::HashSet<DefinitionId> {
+    match ast {
+        Ast::Atom(atom) => free_definition_ids(atom),
+        Ast::FunctionCall(call) => {
+            let mut free = free_definition_ids(&call.function);
+            for arg in &call.args {
+                free = free.union(free_definition_ids(arg));
+            }
+            free
+        },
+        Ast::Let(let_) => {
+            let mut free = free_definition_ids_in_ast(&let_.body);
+            free.remove(&let_.variable);
+            free.union(free_definition_ids_in_ast(&let_.expr))
+        },
+        Ast::If(if_) => free_definition_ids(&if_.condition)
+            .union(free_definition_ids_in_ast(&if_.then))
+            .union(free_definition_ids_in_ast(&if_.otherwise)),
+        Ast::Match(match_) => {
+            let mut free = free_definition_ids_in_decision_tree(&match_.decision_tree);
+            for branch in &match_.branches {
+                free = free.union(free_definition_ids_in_ast(branch));
+            }
+            free
+        },
+        Ast::Return(ret) => free_definition_ids(&ret.expression),
+        Ast::Assignment(assign) => free_definition_ids(&assign.lhs).union(free_definition_ids(&assign.rhs)),
+        Ast::MemberAccess(member) => free_definition_ids(&member.lhs),
+        Ast::Tuple(tuple) => {
+            let mut free = im::HashSet::new();
+            for field in &tuple.fields {
+                free = free.union(free_definition_ids(field));
+            }
+            free
+        },
+        Ast::Builtin(builtin) => free_definition_ids_in_builtin(builtin),
+        Ast::Handle(_) => im::HashSet::new(),
+    }
+}
+
+fn free_definition_ids_in_decision_tree(tree: &mir::DecisionTree) -> im::HashSet<DefinitionId> {
+    match tree {
+        mir::DecisionTree::Leaf(leaf) => {
+            let mut free = im::HashSet::new();
+            for binding in &leaf.bindings {
+                free = free.union(free_definition_ids(binding));
+            }
+            free
+        },
+        mir::DecisionTree::Let(let_) => {
+            let mut free = free_definition_ids_in_decision_tree(&let_.body);
+            free.remove(&let_.variable_to_bind);
+            free.union(free_definition_ids(&let_.expr))
+        },
+        mir::DecisionTree::Switch { int_to_switch_on, cases, else_case } => {
+            let mut free = free_definition_ids(int_to_switch_on);
+            for (_, case) in cases {
+                free = free.union(free_definition_ids_in_decision_tree(case));
+            }
+            if let Some(else_case) = else_case {
+                free = free.union(free_definition_ids_in_decision_tree(else_case));
+            }
+            free
+        },
+    }
+}
+
+fn free_definition_ids_in_builtin(builtin: &mir::Builtin) -> im::HashSet<DefinitionId> {
+    use mir::Builtin;
+    match builtin {
+        Builtin::AddInt(lhs, rhs) | Builtin::AddFloat(lhs, rhs) | Builtin::SubInt(lhs, rhs) | Builtin::SubFloat(lhs, rhs)
+        | Builtin::MulInt(lhs, rhs) | Builtin::MulFloat(lhs, rhs) | Builtin::DivSigned(lhs, rhs) | Builtin::DivUnsigned(lhs, rhs)
+        | Builtin::DivFloat(lhs, rhs) | Builtin::ModSigned(lhs, rhs) | Builtin::ModUnsigned(lhs, rhs) | Builtin::ModFloat(lhs, rhs)
+        | Builtin::LessSigned(lhs, rhs) | Builtin::LessUnsigned(lhs, rhs) | Builtin::LessFloat(lhs, rhs) | Builtin::EqInt(lhs, rhs)
+        | Builtin::EqFloat(lhs, rhs) | Builtin::EqChar(lhs, rhs) | Builtin::EqBool(lhs, rhs) | Builtin::BitwiseAnd(lhs, rhs)
+        | Builtin::BitwiseOr(lhs, rhs) | Builtin::BitwiseXor(lhs, rhs) => free_definition_ids(lhs).union(free_definition_ids(rhs)),
+        Builtin::SignExtend(lhs, _) | Builtin::ZeroExtend(lhs, _) | Builtin::SignedToFloat(lhs, _) | Builtin::UnsignedToFloat(lhs, _)
+        | Builtin::FloatToSigned(lhs, _) | Builtin::FloatToUnsigned(lhs, _) | Builtin::FloatPromote(lhs, _) | Builtin::FloatDemote(lhs, _)
+        | Builtin::BitwiseNot(lhs) | Builtin::StackAlloc(lhs) | Builtin::Truncate(lhs, _) | Builtin::Deref(lhs, _)
+        | Builtin::Transmute(lhs, _) => free_definition_ids(lhs),
+        Builtin::Offset(lhs, rhs, _) => free_definition_ids(lhs).union(free_definition_ids(rhs)),
+    }
+}
+
+/// Replace every occurrence of `old` with `new` in `ast`, stopping at any inner binder that
+/// rebinds `old` (that subtree's `old` refers to the inner binding, not the one being renamed).
+fn rename_in_ast(ast: Ast, old: DefinitionId, new: DefinitionId) -> Ast {
+    match ast {
+        Ast::Atom(atom) => Ast::Atom(rename_in_atom(atom, old, new)),
+        Ast::FunctionCall(mut call) => {
+            call.function = rename_in_atom(call.function, old, new);
+            call.args = fmap(call.args, |arg| rename_in_atom(arg, old, new));
+            Ast::FunctionCall(call)
+        },
+        Ast::Let(mut let_) => {
+            *let_.expr = rename_in_ast(*let_.expr, old, new);
+            if let_.variable != old {
+                *let_.body = rename_in_ast(*let_.body, old, new);
+            }
+            Ast::Let(let_)
+        },
+        Ast::If(mut if_) => {
+            if_.condition = rename_in_atom(if_.condition, old, new);
+            *if_.then = rename_in_ast(*if_.then, old, new);
+            *if_.otherwise = rename_in_ast(*if_.otherwise, old, new);
+            Ast::If(if_)
+        },
+        Ast::Match(mut match_) => {
+            match_.decision_tree = rename_in_decision_tree(match_.decision_tree, old, new);
+            match_.branches = fmap(match_.branches, |branch| rename_in_ast(branch, old, new));
+            Ast::Match(match_)
+        },
+        Ast::Return(mut ret) => {
+            ret.expression = rename_in_atom(ret.expression, old, new);
+            Ast::Return(ret)
+        },
+        Ast::Assignment(mut assign) => {
+            assign.lhs = rename_in_atom(assign.lhs, old, new);
+            assign.rhs = rename_in_atom(assign.rhs, old, new);
+            Ast::Assignment(assign)
+        },
+        Ast::MemberAccess(mut member) => {
+            member.lhs = rename_in_atom(member.lhs, old, new);
+            Ast::MemberAccess(member)
+        },
+        Ast::Tuple(mut tuple) => {
+            tuple.fields = fmap(tuple.fields, |field| rename_in_atom(field, old, new));
+            Ast::Tuple(tuple)
+        },
+        Ast::Builtin(builtin) => Ast::Builtin(rename_in_builtin(builtin, old, new)),
+        Ast::Handle(handle) => Ast::Handle(handle),
+    }
+}
+
+/// Like `rename_in_ast`, but for the bindings and switch arms of a `DecisionTree`, stopping at
+/// any inner `DecisionTree::Let` that rebinds `old` the same way `rename_in_ast` stops at a
+/// rebinding `Ast::Let`.
+fn rename_in_decision_tree(tree: mir::DecisionTree, old: DefinitionId, new: DefinitionId) -> mir::DecisionTree {
+    match tree {
+        mir::DecisionTree::Leaf(mut leaf) => {
+            leaf.bindings = fmap(leaf.bindings, |binding| rename_in_atom(binding, old, new));
+            mir::DecisionTree::Leaf(leaf)
+        },
+        mir::DecisionTree::Let(mut let_) => {
+            let_.expr = rename_in_atom(let_.expr, old, new);
+            if let_.variable_to_bind != old {
+                *let_.body = rename_in_decision_tree(*let_.body, old, new);
+            }
+            mir::DecisionTree::Let(let_)
+        },
+        mir::DecisionTree::Switch { int_to_switch_on, cases, else_case } => {
+            let int_to_switch_on = rename_in_atom(int_to_switch_on, old, new);
+            let cases = fmap(cases, |(tag, case)| (tag, rename_in_decision_tree(case, old, new)));
+            let else_case = else_case.map(|case| Box::new(rename_in_decision_tree(*case, old, new)));
+            mir::DecisionTree::Switch { int_to_switch_on, cases, else_case }
+        },
+    }
+}
+
+fn rename_in_atom(atom: Atom, old: DefinitionId, new: DefinitionId) -> Atom {
+    match atom {
+        Atom::Literal(literal) => Atom::Literal(literal),
+        Atom::Variable(mut variable) => {
+            if variable.definition_id == old {
+                variable.definition_id = new;
+            }
+            Atom::Variable(variable)
+        },
+        Atom::Lambda(mut lambda) => {
+            // If this lambda rebinds `old` itself, its body is a separate scope where `old`
+            // refers to this lambda's own argument, not the one being renamed.
+            if !lambda.args.iter().any(|arg| arg.definition_id == old) {
+                *lambda.body = rename_in_ast(*lambda.body, old, new);
+            }
+            Atom::Lambda(lambda)
+        },
+        Atom::Extern(extern_) => Atom::Extern(extern_),
+        Atom::Effect(effect) => Atom::Effect(effect),
+    }
+}
+
+fn rename_in_builtin(builtin: mir::Builtin, old: DefinitionId, new: DefinitionId) -> mir::Builtin {
+    use mir::Builtin;
+
+    let both = |f: fn(_, _) -> Builtin, lhs: Atom, rhs: Atom| f(rename_in_atom(lhs, old, new), rename_in_atom(rhs, old, new));
+    let one_with_type = |f: fn(_, _) -> Builtin, lhs: Atom, typ| f(rename_in_atom(lhs, old, new), typ);
+    let one = |f: fn(_) -> Builtin, lhs: Atom| f(rename_in_atom(lhs, old, new));
+
+    match builtin {
+        Builtin::AddInt(lhs, rhs) => both(Builtin::AddInt, lhs, rhs),
+        Builtin::AddFloat(lhs, rhs) => both(Builtin::AddFloat, lhs, rhs),
+        Builtin::SubInt(lhs, rhs) => both(Builtin::SubInt, lhs, rhs),
+        Builtin::SubFloat(lhs, rhs) => both(Builtin::SubFloat, lhs, rhs),
+        Builtin::MulInt(lhs, rhs) => both(Builtin::MulInt, lhs, rhs),
+        Builtin::MulFloat(lhs, rhs) => both(Builtin::MulFloat, lhs, rhs),
+        Builtin::DivSigned(lhs, rhs) => both(Builtin::DivSigned, lhs, rhs),
+        Builtin::DivUnsigned(lhs, rhs) => both(Builtin::DivUnsigned, lhs, rhs),
+        Builtin::DivFloat(lhs, rhs) => both(Builtin::DivFloat, lhs, rhs),
+        Builtin::ModSigned(lhs, rhs) => both(Builtin::ModSigned, lhs, rhs),
+        Builtin::ModUnsigned(lhs, rhs) => both(Builtin::ModUnsigned, lhs, rhs),
+        Builtin::ModFloat(lhs, rhs) => both(Builtin::ModFloat, lhs, rhs),
+        Builtin::LessSigned(lhs, rhs) => both(Builtin::LessSigned, lhs, rhs),
+        Builtin::LessUnsigned(lhs, rhs) => both(Builtin::LessUnsigned, lhs, rhs),
+        Builtin::LessFloat(lhs, rhs) => both(Builtin::LessFloat, lhs, rhs),
+        Builtin::EqInt(lhs, rhs) => both(Builtin::EqInt, lhs, rhs),
+        Builtin::EqFloat(lhs, rhs) => both(Builtin::EqFloat, lhs, rhs),
+        Builtin::EqChar(lhs, rhs) => both(Builtin::EqChar, lhs, rhs),
+        Builtin::EqBool(lhs, rhs) => both(Builtin::EqBool, lhs, rhs),
+        Builtin::SignExtend(lhs, rhs) => one_with_type(Builtin::SignExtend, lhs, rhs),
+        Builtin::ZeroExtend(lhs, rhs) => one_with_type(Builtin::ZeroExtend, lhs, rhs),
+        Builtin::SignedToFloat(lhs, rhs) => one_with_type(Builtin::SignedToFloat, lhs, rhs),
+        Builtin::UnsignedToFloat(lhs, rhs) => one_with_type(Builtin::UnsignedToFloat, lhs, rhs),
+        Builtin::FloatToSigned(lhs, rhs) => one_with_type(Builtin::FloatToSigned, lhs, rhs),
+        Builtin::FloatToUnsigned(lhs, rhs) => one_with_type(Builtin::FloatToUnsigned, lhs, rhs),
+        Builtin::FloatPromote(lhs, rhs) => one_with_type(Builtin::FloatPromote, lhs, rhs),
+        Builtin::FloatDemote(lhs, rhs) => one_with_type(Builtin::FloatDemote, lhs, rhs),
+        Builtin::BitwiseAnd(lhs, rhs) => both(Builtin::BitwiseAnd, lhs, rhs),
+        Builtin::BitwiseOr(lhs, rhs) => both(Builtin::BitwiseOr, lhs, rhs),
+        Builtin::BitwiseXor(lhs, rhs) => both(Builtin::BitwiseXor, lhs, rhs),
+        Builtin::BitwiseNot(lhs) => one(Builtin::BitwiseNot, lhs),
+        Builtin::StackAlloc(lhs) => one(Builtin::StackAlloc, lhs),
+        Builtin::Truncate(lhs, rhs) => one_with_type(Builtin::Truncate, lhs, rhs),
+        Builtin::Deref(lhs, rhs) => one_with_type(Builtin::Deref, lhs, rhs),
+        Builtin::Transmute(lhs, rhs) => one_with_type(Builtin::Transmute, lhs, rhs),
+        Builtin::Offset(lhs, rhs, typ) => Builtin::Offset(rename_in_atom(lhs, old, new), rename_in_atom(rhs, old, new), typ),
+    }
+}
+
 impl Evaluate<Atom> for mir::Extern {
-    fn evaluate(self, _: &Substitutions) -> Atom {
+    fn evaluate(self, _: &Context) -> Atom {
         Atom::Extern(self)
     }
 }
 
 impl Evaluate<Ast> for mir::FunctionCall {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        let function = self.function.evaluate(substitutions);
-        let args = fmap(self.args, |arg| arg.evaluate(substitutions));
+    fn evaluate(mut self, context: &Context) -> Ast {
+        let function = self.function.evaluate(context);
+        let args = fmap(self.args, |arg| arg.evaluate(context));
 
         match function {
-            Atom::Lambda(lambda) if lambda.compile_time || self.compile_time => {
-                let mut new_substitutions = substitutions.clone();
+            Atom::Lambda(lambda) if (lambda.compile_time || self.compile_time) && context.consume_fuel() => {
+                let mut substitutions = context.substitutions.clone();
                 assert_eq!(lambda.args.len(), args.len());
 
                 for (param, arg) in lambda.args.iter().zip(args) {
-                    new_substitutions.insert(param.definition_id, arg);
+                    substitutions.insert(param.definition_id, arg);
                 }
 
-                lambda.body.evaluate(&new_substitutions).evaluate(substitutions)
+                let inner_context = context.with_substitutions(substitutions);
+                lambda.body.evaluate(&inner_context).evaluate(context)
+            }
+            // Out of fuel: leave this call as a residual `Ast::FunctionCall` instead of
+            // inlining it further, turning what would otherwise be an infinite loop on a
+            // recursive `compile_time` function into a bounded, diagnosable failure.
+            Atom::Lambda(lambda) if lambda.compile_time || self.compile_time => {
+                // `replace` reports whether a warning was already printed, so this residual's
+                // many revisits (see `warned_out_of_fuel`'s doc comment) only print once.
+                if !context.warned_out_of_fuel.replace(true) {
+                    eprintln!(
+                        "warning: a compile_time function exceeded the evaluation limit of {} steps and was left un-inlined",
+                        context.total_fuel
+                    );
+                }
+                self.function = Atom::Lambda(lambda);
+                self.args = args;
+                Ast::FunctionCall(self)
             }
             function => {
                 self.function = function;
@@ -99,88 +449,168 @@ impl Evaluate<Ast> for mir::FunctionCall {
 }
 
 impl Evaluate<Ast> for mir::Let<Ast> {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        *self.expr = self.expr.evaluate(substitutions);
-        *self.body = self.body.evaluate(substitutions);
+    fn evaluate(mut self, context: &Context) -> Ast {
+        *self.expr = self.expr.evaluate(context);
+
+        // A literal bound here (e.g. a comparison folded by `fold_builtin`) is substituted into
+        // the body so an `If` on it can be statically eliminated. As in `DecisionTree::Let`, the
+        // binding is alpha-renamed first if a pending substitution has it free.
+        if let Ast::Atom(literal @ Atom::Literal(_)) = self.expr.as_ref() {
+            let mut substitutions = context.substitutions.clone();
+            if substitutions.values().any(|value| free_definition_ids(value).contains(&self.variable)) {
+                let fresh_id = fresh_definition_id();
+                *self.body = rename_in_ast(*self.body, self.variable, fresh_id);
+                self.variable = fresh_id;
+            }
+            substitutions.insert(self.variable, literal.clone());
+            *self.body = self.body.evaluate(&context.with_substitutions(substitutions));
+        } else {
+            *self.body = self.body.evaluate(context);
+        }
         Ast::Let(self)
     }
 }
 
 impl Evaluate<Ast> for mir::If {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        self.condition = self.condition.evaluate(substitutions);
-        *self.then = self.then.evaluate(substitutions);
-        *self.otherwise = self.otherwise.evaluate(substitutions);
-        Ast::If(self)
+    fn evaluate(mut self, context: &Context) -> Ast {
+        self.condition = self.condition.evaluate(context);
+
+        // If the condition is statically known, only evaluate the taken branch and drop the
+        // other entirely, mirroring how a tree-walking evaluator short-circuits a conditional.
+        // This is the main payoff of the static-call pass: a compile-time-known comparison
+        // (e.g. `EqInt(Literal, Literal)` folded by `fold_builtin`) feeding an `If` collapses
+        // the whole branch away instead of just folding the comparison itself.
+        match condition_as_bool(&self.condition) {
+            Some(true) => self.then.evaluate(context),
+            Some(false) => self.otherwise.evaluate(context),
+            None => {
+                *self.then = self.then.evaluate(context);
+                *self.otherwise = self.otherwise.evaluate(context);
+                Ast::If(self)
+            },
+        }
+    }
+}
+
+/// If `condition` is a literal boolean, or a literal integer interpreted as a boolean (zero is
+/// false, anything else is true), return the value it statically reduces to.
+fn condition_as_bool(condition: &Atom) -> Option<bool> {
+    match condition {
+        Atom::Literal(mir::Literal::Bool(value)) => Some(*value),
+        Atom::Literal(mir::Literal::Integer(value, _)) => Some(*value != 0),
+        _ => None,
     }
 }
 
 impl Evaluate<Ast> for mir::Match {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        self.decision_tree = evaluate_decision_tree(self.decision_tree, substitutions);
-        self.branches = fmap(self.branches, |branch| branch.evaluate(substitutions));
+    fn evaluate(mut self, context: &Context) -> Ast {
+        self.decision_tree = evaluate_decision_tree(self.decision_tree, context);
+        self.branches = fmap(self.branches, |branch| branch.evaluate(context));
         Ast::Match(self)
     }
 }
 
-fn evaluate_decision_tree(tree: mir::DecisionTree, substitutions: &Substitutions) -> mir::DecisionTree {
+fn evaluate_decision_tree(tree: mir::DecisionTree, context: &Context) -> mir::DecisionTree {
     match tree {
-        mir::DecisionTree::Leaf(_) => todo!(),
-        mir::DecisionTree::Let(_) => todo!(),
-        mir::DecisionTree::Switch { int_to_switch_on, cases, else_case } => todo!(),
+        mir::DecisionTree::Leaf(mut leaf) => {
+            leaf.bindings = fmap(leaf.bindings, |atom| atom.evaluate(context));
+            mir::DecisionTree::Leaf(leaf)
+        },
+        // Shadows any matching variables in `substitutions`, same as `mir::Lambda` does for its
+        // args. As with `mir::Lambda`, shadowing alone isn't enough to prevent capture: if a
+        // pending substitution's value has `variable_to_bind` free, evaluating that substitution
+        // under this `Let`'s body would capture it. Alpha-rename to a fresh id first in that case.
+        mir::DecisionTree::Let(mut let_) => {
+            let_.expr = let_.expr.evaluate(context);
+
+            let mut substitutions = context.substitutions.clone();
+
+            if substitutions.values().any(|value| free_definition_ids(value).contains(&let_.variable_to_bind)) {
+                let fresh_id = fresh_definition_id();
+                *let_.body = rename_in_decision_tree(*let_.body, let_.variable_to_bind, fresh_id);
+                let_.variable_to_bind = fresh_id;
+            }
+
+            substitutions.insert(let_.variable_to_bind, let_.expr.clone());
+            let context = context.with_substitutions(substitutions);
+
+            *let_.body = evaluate_decision_tree(*let_.body, &context);
+            mir::DecisionTree::Let(let_)
+        },
+        mir::DecisionTree::Switch { int_to_switch_on, cases, else_case } => {
+            match int_to_switch_on.evaluate(context) {
+                // The switched-on value is statically known: select the matching case (or the
+                // else case) and discard the rest of the switch, this is what actually removes
+                // the matched-away branches at compile time.
+                Atom::Literal(mir::Literal::Integer(tag, _)) if cases.iter().any(|(case_tag, _)| *case_tag as u64 == tag) => {
+                    let (_, case) = cases.into_iter().find(|(case_tag, _)| *case_tag as u64 == tag).unwrap();
+                    evaluate_decision_tree(case, context)
+                },
+                Atom::Literal(_) => match else_case {
+                    Some(else_case) => evaluate_decision_tree(*else_case, context),
+                    None => unreachable!("Switch on a known literal matched no case and had no else_case"),
+                },
+                // Not statically known: recurse into every case and the else case, rebuilding the switch.
+                int_to_switch_on => {
+                    let cases = fmap(cases, |(tag, case)| (tag, evaluate_decision_tree(case, context)));
+                    let else_case = else_case.map(|case| Box::new(evaluate_decision_tree(*case, context)));
+                    mir::DecisionTree::Switch { int_to_switch_on, cases, else_case }
+                },
+            }
+        },
     }
 }
 
 impl Evaluate<Ast> for mir::Return {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        self.expression = self.expression.evaluate(substitutions);
+    fn evaluate(mut self, context: &Context) -> Ast {
+        self.expression = self.expression.evaluate(context);
         Ast::Return(self)
     }
 }
 
 impl Evaluate<Ast> for mir::Assignment {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        self.lhs = self.lhs.evaluate(substitutions);
-        self.rhs = self.rhs.evaluate(substitutions);
+    fn evaluate(mut self, context: &Context) -> Ast {
+        self.lhs = self.lhs.evaluate(context);
+        self.rhs = self.rhs.evaluate(context);
         Ast::Assignment(self)
     }
 }
 
 impl Evaluate<Ast> for mir::MemberAccess {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        self.lhs = self.lhs.evaluate(substitutions);
+    fn evaluate(mut self, context: &Context) -> Ast {
+        self.lhs = self.lhs.evaluate(context);
         Ast::MemberAccess(self)
     }
 }
 
 impl Evaluate<Ast> for mir::Tuple {
-    fn evaluate(mut self, substitutions: &Substitutions) -> Ast {
-        self.fields = fmap(self.fields, |field| field.evaluate(substitutions));
+    fn evaluate(mut self, context: &Context) -> Ast {
+        self.fields = fmap(self.fields, |field| field.evaluate(context));
         Ast::Tuple(self)
     }
 }
 
 impl Evaluate<Ast> for mir::Builtin {
-    fn evaluate(self, substitutions: &Substitutions) -> Ast {
+    fn evaluate(self, context: &Context) -> Ast {
         use mir::Builtin;
 
         let both = |f: fn(_, _) -> Builtin, lhs: Atom, rhs: Atom| {
-            let lhs = lhs.evaluate(substitutions);
-            let rhs = rhs.evaluate(substitutions);
-            Ast::Builtin(f(lhs, rhs))
+            let lhs = lhs.evaluate(context);
+            let rhs = rhs.evaluate(context);
+            f(lhs, rhs)
         };
 
         let one_with_type = |f: fn(_, _) -> Builtin, lhs: Atom, typ| {
-            let lhs = lhs.evaluate(substitutions);
-            Ast::Builtin(f(lhs, typ))
+            let lhs = lhs.evaluate(context);
+            f(lhs, typ)
         };
 
         let one = |f: fn(_) -> Builtin, lhs: Atom| {
-            let lhs = lhs.evaluate(substitutions);
-            Ast::Builtin(f(lhs))
+            let lhs = lhs.evaluate(context);
+            f(lhs)
         };
 
-        match self {
+        let builtin = match self {
             Builtin::AddInt(lhs, rhs) => both(Builtin::AddInt, lhs, rhs),
             Builtin::AddFloat(lhs, rhs) => both(Builtin::AddFloat, lhs, rhs),
             Builtin::SubInt(lhs, rhs) => both(Builtin::SubInt, lhs, rhs),
@@ -217,22 +647,527 @@ impl Evaluate<Ast> for mir::Builtin {
             Builtin::Deref(lhs, rhs) => one_with_type(Builtin::Deref, lhs, rhs),
             Builtin::Transmute(lhs, rhs) => one_with_type(Builtin::Transmute, lhs, rhs),
             Builtin::Offset(lhs, rhs, typ) => {
-                let lhs = lhs.evaluate(substitutions);
-                let rhs = rhs.evaluate(substitutions);
-                Ast::Builtin(Builtin::Offset(lhs, rhs, typ))
+                let lhs = lhs.evaluate(context);
+                let rhs = rhs.evaluate(context);
+                Builtin::Offset(lhs, rhs, typ)
             },
-        }
+        };
+
+        fold_builtin(builtin)
+    }
+}
+
+/// Bit width of an integer kind, used to wrap arithmetic results the same way the target
+/// machine integer would.
+fn integer_bit_width(kind: mir::IntegerKind) -> u32 {
+    use mir::IntegerKind::*;
+    match kind {
+        I8 | U8 => 8,
+        I16 | U16 => 16,
+        I32 | U32 => 32,
+        I64 | U64 | Isz | Usz => 64,
+    }
+}
+
+/// Wrap a 128-bit intermediate result down to the bit width of `kind`, matching the
+/// two's-complement wrapping the target machine integer would perform instead of Rust's
+/// native overflow panics.
+fn wrap_to_kind(value: i128, kind: mir::IntegerKind) -> u64 {
+    let bits = integer_bit_width(kind);
+    if bits >= 128 {
+        value as u64
+    } else {
+        (value & ((1i128 << bits) - 1)) as u64
+    }
+}
+
+/// Reinterpret the raw bits of an integer literal as a signed value of its own bit width.
+fn as_signed(value: u64, kind: mir::IntegerKind) -> i128 {
+    let bits = integer_bit_width(kind);
+    let value = value as i128;
+    let sign_bit = 1i128 << (bits - 1);
+    if value & sign_bit != 0 { value - (1i128 << bits) } else { value }
+}
+
+/// Cast `value` to the signed integer type named by `kind`, using Rust's own (saturating,
+/// NaN-to-zero) float-to-int cast for that exact width rather than a fixed-width intermediate:
+/// casting out-of-range floats through `i128` first and then wrapping to `kind`'s width (the way
+/// `wrap_to_kind` wraps integer arithmetic) gives a different, wrong result for any float outside
+/// `kind`'s own range, e.g. `1000.0 as i8` saturates to `127` but wraps to `-24`. The result comes
+/// back in the same zero-extended bit-pattern representation `wrap_to_kind` uses for other
+/// integer literals.
+fn float_to_signed(value: f64, kind: mir::IntegerKind) -> u64 {
+    use mir::IntegerKind::*;
+    match kind {
+        I8 => (value as i8) as u8 as u64,
+        I16 => (value as i16) as u16 as u64,
+        I32 => (value as i32) as u32 as u64,
+        I64 | Isz => value as i64 as u64,
+        U8 | U16 | U32 | U64 | Usz => unreachable!("FloatToSigned with an unsigned target kind"),
+    }
+}
+
+/// Like `float_to_signed`, but for the unsigned integer type named by `kind`.
+fn float_to_unsigned(value: f64, kind: mir::IntegerKind) -> u64 {
+    use mir::IntegerKind::*;
+    match kind {
+        U8 => value as u8 as u64,
+        U16 => value as u16 as u64,
+        U32 => value as u32 as u64,
+        U64 | Usz => value as u64,
+        I8 | I16 | I32 | I64 | Isz => unreachable!("FloatToUnsigned with a signed target kind"),
+    }
+}
+
+fn int_literal(atom: &Atom) -> Option<(u64, mir::IntegerKind)> {
+    match atom {
+        Atom::Literal(mir::Literal::Integer(value, kind)) => Some((*value, *kind)),
+        _ => None,
+    }
+}
+
+fn float_literal(atom: &Atom) -> Option<(f64, mir::FloatKind)> {
+    match atom {
+        Atom::Literal(mir::Literal::Float(value, kind)) => Some((*value, *kind)),
+        _ => None,
     }
 }
 
+/// Constant-fold a builtin whose operands have already been evaluated, similar to clippy's
+/// `consts.rs` constant evaluator: if every operand is a literal, compute the result at
+/// compile time instead of leaving the builtin to be computed at runtime.
+///
+/// Division and modulo by a literal zero are deliberately left unfolded so the runtime
+/// trap/semantics for that case are preserved; integer arithmetic wraps at the operation's bit
+/// width rather than panicking the way Rust's own arithmetic would; and float-to-int casts
+/// saturate at the target width (see `float_to_signed`/`float_to_unsigned`) rather than wrapping,
+/// matching what a native `as` cast to that width would do.
+fn fold_builtin(builtin: mir::Builtin) -> Ast {
+    use mir::{ Builtin, Literal, IntegerKind };
+
+    let int = |value: i128, kind: IntegerKind| Ast::Atom(Atom::Literal(Literal::Integer(wrap_to_kind(value, kind), kind)));
+    let bool_ = |value: bool| Ast::Atom(Atom::Literal(Literal::Bool(value)));
+    let float = |value: f64, kind: mir::FloatKind| Ast::Atom(Atom::Literal(Literal::Float(value, kind)));
+    let signed_int = |value: f64, kind: IntegerKind| Ast::Atom(Atom::Literal(Literal::Integer(float_to_signed(value, kind), kind)));
+    let unsigned_int = |value: f64, kind: IntegerKind| Ast::Atom(Atom::Literal(Literal::Integer(float_to_unsigned(value, kind), kind)));
+
+    match &builtin {
+        Builtin::AddInt(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return int(as_signed(l, k) + as_signed(r, k), k);
+        },
+        Builtin::SubInt(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return int(as_signed(l, k) - as_signed(r, k), k);
+        },
+        Builtin::MulInt(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return int(as_signed(l, k) * as_signed(r, k), k);
+        },
+        Builtin::DivSigned(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            if r != 0 {
+                return int(as_signed(l, k) / as_signed(r, k), k);
+            }
+        },
+        Builtin::DivUnsigned(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            if r != 0 {
+                return int((l / r) as i128, k);
+            }
+        },
+        Builtin::ModSigned(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            if r != 0 {
+                return int(as_signed(l, k) % as_signed(r, k), k);
+            }
+        },
+        Builtin::ModUnsigned(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            if r != 0 {
+                return int((l % r) as i128, k);
+            }
+        },
+        Builtin::LessSigned(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return bool_(as_signed(l, k) < as_signed(r, k));
+        },
+        Builtin::LessUnsigned(lhs, rhs) => if let (Some((l, _)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return bool_(l < r);
+        },
+        Builtin::EqInt(lhs, rhs) => if let (Some((l, _)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return bool_(l == r);
+        },
+        Builtin::BitwiseAnd(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return int((l & r) as i128, k);
+        },
+        Builtin::BitwiseOr(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return int((l | r) as i128, k);
+        },
+        Builtin::BitwiseXor(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (int_literal(lhs), int_literal(rhs)) {
+            return int((l ^ r) as i128, k);
+        },
+        Builtin::BitwiseNot(lhs) => if let Some((l, k)) = int_literal(lhs) {
+            return int(!l as i128, k);
+        },
+        Builtin::AddFloat(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            return float(l + r, k);
+        },
+        Builtin::SubFloat(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            return float(l - r, k);
+        },
+        Builtin::MulFloat(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            return float(l * r, k);
+        },
+        Builtin::DivFloat(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            if r != 0.0 {
+                return float(l / r, k);
+            }
+        },
+        Builtin::ModFloat(lhs, rhs) => if let (Some((l, k)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            if r != 0.0 {
+                return float(l % r, k);
+            }
+        },
+        Builtin::LessFloat(lhs, rhs) => if let (Some((l, _)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            return bool_(l < r);
+        },
+        Builtin::EqFloat(lhs, rhs) => if let (Some((l, _)), Some((r, _))) = (float_literal(lhs), float_literal(rhs)) {
+            return bool_(l == r);
+        },
+        Builtin::EqChar(lhs, rhs) => if let (Atom::Literal(Literal::Char(l)), Atom::Literal(Literal::Char(r))) = (lhs, rhs) {
+            return bool_(l == r);
+        },
+        Builtin::EqBool(lhs, rhs) => if let (Atom::Literal(Literal::Bool(l)), Atom::Literal(Literal::Bool(r))) = (lhs, rhs) {
+            return bool_(l == r);
+        },
+        Builtin::SignExtend(lhs, target) => if let Some((l, k)) = int_literal(lhs) {
+            return int(as_signed(l, k), *target);
+        },
+        Builtin::ZeroExtend(lhs, target) => if let Some((l, _)) = int_literal(lhs) {
+            return int(l as i128, *target);
+        },
+        Builtin::Truncate(lhs, target) => if let Some((l, _)) = int_literal(lhs) {
+            return int(l as i128, *target);
+        },
+        Builtin::SignedToFloat(lhs, target) => if let Some((l, k)) = int_literal(lhs) {
+            return float(as_signed(l, k) as f64, *target);
+        },
+        Builtin::UnsignedToFloat(lhs, target) => if let Some((l, _)) = int_literal(lhs) {
+            return float(l as f64, *target);
+        },
+        Builtin::FloatToSigned(lhs, target) => if let Some((l, _)) = float_literal(lhs) {
+            return signed_int(l, *target);
+        },
+        Builtin::FloatToUnsigned(lhs, target) => if let Some((l, _)) = float_literal(lhs) {
+            return unsigned_int(l, *target);
+        },
+        Builtin::FloatPromote(lhs, target) | Builtin::FloatDemote(lhs, target) => if let Some((l, _)) = float_literal(lhs) {
+            return float(l, *target);
+        },
+        // Not constant-foldable: these builtins have side effects or depend on runtime memory.
+        Builtin::StackAlloc(_) | Builtin::Deref(..) | Builtin::Transmute(..) | Builtin::Offset(..) => {},
+    }
+
+    Ast::Builtin(builtin)
+}
+
 impl Evaluate<Atom> for mir::Effect {
-    fn evaluate(self, _: &Substitutions) -> Atom {
+    fn evaluate(self, _: &Context) -> Atom {
         unreachable!("Effect nodes should be removed by the mir-cps pass before evaluation")
     }
 }
 
 impl Evaluate<Ast> for mir::Handle {
-    fn evaluate(self, _: &Substitutions) -> Ast {
+    fn evaluate(self, _: &Context) -> Ast {
         unreachable!("Handle expressions should be removed by the mir-cps pass before evaluation")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variable(id: u32) -> mir::Variable {
+        mir::Variable { definition_id: DefinitionId(id) }
+    }
+
+    fn variable_atom(id: u32) -> Atom {
+        Atom::Variable(variable(id))
+    }
+
+    fn context_with_substitution(from: u32, to: Atom) -> Context {
+        let context = Context::new(DEFAULT_EVALUATION_FUEL);
+        context.with_substitutions(im::HashMap::unit(DefinitionId(from), to))
+    }
+
+    fn decision_tree_let(variable_to_bind: u32, expr: Atom, body: mir::DecisionTree) -> mir::DecisionTree {
+        mir::DecisionTree::Let(mir::DecisionTreeLet {
+            variable_to_bind: DefinitionId(variable_to_bind),
+            expr,
+            body: Box::new(body),
+        })
+    }
+
+    fn leaf(id: u32) -> mir::DecisionTree {
+        mir::DecisionTree::Leaf(mir::DecisionTreeLeaf { bindings: vec![variable_atom(id)] })
+    }
+
+    // Regression test for `mir::If::evaluate`'s constant-condition elimination: a statically known
+    // condition must select exactly the taken branch, with the other branch dropped rather than
+    // evaluated and left behind.
+    #[test]
+    fn if_with_true_condition_selects_the_then_branch() {
+        let if_ = mir::If {
+            condition: Atom::Literal(mir::Literal::Bool(true)),
+            then: Box::new(Ast::Atom(variable_atom(1))),
+            otherwise: Box::new(Ast::Atom(variable_atom(2))),
+        };
+
+        let context = Context::new(DEFAULT_EVALUATION_FUEL);
+        let result = if_.evaluate(&context);
+
+        match result {
+            Ast::Atom(Atom::Variable(variable)) => assert_eq!(variable.definition_id, DefinitionId(1)),
+            other => panic!("expected the `then` branch, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn if_with_false_condition_selects_the_otherwise_branch() {
+        let if_ = mir::If {
+            condition: Atom::Literal(mir::Literal::Bool(false)),
+            then: Box::new(Ast::Atom(variable_atom(1))),
+            otherwise: Box::new(Ast::Atom(variable_atom(2))),
+        };
+
+        let context = Context::new(DEFAULT_EVALUATION_FUEL);
+        let result = if_.evaluate(&context);
+
+        match result {
+            Ast::Atom(Atom::Variable(variable)) => assert_eq!(variable.definition_id, DefinitionId(2)),
+            other => panic!("expected the `otherwise` branch, got {other:?}"),
+        }
+    }
+
+    fn compile_time_call() -> mir::FunctionCall {
+        let lambda = mir::Lambda { args: vec![], body: Box::new(Ast::Atom(variable_atom(1))), compile_time: true };
+        mir::FunctionCall { function: Atom::Lambda(lambda), args: vec![], compile_time: false }
+    }
+
+    // Regression test for the out-of-fuel diagnostic: the same residual `FunctionCall` is
+    // revisited once per stack frame an outer `.evaluate` unwinds through (see
+    // `warned_out_of_fuel`'s doc comment), but the warning itself must only ever fire once per
+    // top-level function, tracked by `Context::warned_out_of_fuel`.
+    #[test]
+    fn out_of_fuel_warning_fires_at_most_once_per_function() {
+        let context = Context::new(0);
+        assert!(!context.warned_out_of_fuel.get(), "fresh context should start out unwarned");
+
+        compile_time_call().evaluate(&context);
+        assert!(context.warned_out_of_fuel.get(), "first out-of-fuel visit should flag the warning as printed");
+
+        // A later visit to another residual of the same kind (standing in for an outer unwind
+        // re-walking the one already built) must leave the flag as already-set, not reset it.
+        compile_time_call().evaluate(&context);
+        assert!(context.warned_out_of_fuel.get());
+    }
+
+    #[test]
+    fn zero_fuel_still_leaves_a_residual_function_call() {
+        let context = Context::new(0);
+        let result = compile_time_call().evaluate(&context);
+        assert!(matches!(result, Ast::FunctionCall(_)), "an exhausted compile_time call must be left un-inlined, got {result:?}");
+    }
+
+    // Regression test for `evaluate_decision_tree`'s `Switch` arm: switching on a statically
+    // known tag must select exactly the matching case, dropping every other case (and the
+    // `else_case`) entirely rather than leaving the whole switch behind.
+    #[test]
+    fn switch_on_known_tag_selects_the_matching_case_and_drops_the_rest() {
+        let tree = mir::DecisionTree::Switch {
+            int_to_switch_on: Atom::Literal(mir::Literal::Integer(1, mir::IntegerKind::U64)),
+            cases: vec![(0, leaf(100)), (1, leaf(200))],
+            else_case: Some(Box::new(leaf(300))),
+        };
+
+        let context = Context::new(DEFAULT_EVALUATION_FUEL);
+        let result = evaluate_decision_tree(tree, &context);
+
+        match result {
+            mir::DecisionTree::Leaf(leaf) => match &leaf.bindings[0] {
+                Atom::Variable(variable) => assert_eq!(variable.definition_id, DefinitionId(200), "only the case tagged 1 should survive"),
+                other => panic!("expected a residual reference to the selected case, got {other:?}"),
+            },
+            other => panic!("expected the switch to collapse to the matching case's Leaf, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn switch_on_known_tag_falls_back_to_else_case_when_no_case_matches() {
+        let tree = mir::DecisionTree::Switch {
+            int_to_switch_on: Atom::Literal(mir::Literal::Integer(2, mir::IntegerKind::U64)),
+            cases: vec![(0, leaf(100)), (1, leaf(200))],
+            else_case: Some(Box::new(leaf(300))),
+        };
+
+        let context = Context::new(DEFAULT_EVALUATION_FUEL);
+        let result = evaluate_decision_tree(tree, &context);
+
+        match result {
+            mir::DecisionTree::Leaf(leaf) => match &leaf.bindings[0] {
+                Atom::Variable(variable) => assert_eq!(variable.definition_id, DefinitionId(300)),
+                other => panic!("expected a residual reference to the else case, got {other:?}"),
+            },
+            other => panic!("expected the switch to collapse to the else case's Leaf, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn fold_builtin_leaves_division_by_zero_unfolded() {
+        let builtin = mir::Builtin::DivSigned(
+            Atom::Literal(mir::Literal::Integer(10, mir::IntegerKind::I32)),
+            Atom::Literal(mir::Literal::Integer(0, mir::IntegerKind::I32)),
+        );
+
+        let result = fold_builtin(builtin);
+
+        assert!(
+            matches!(result, Ast::Builtin(mir::Builtin::DivSigned(..))),
+            "division by a literal zero must stay unfolded so the runtime trap still fires, got {result:?}"
+        );
+    }
+
+    #[test]
+    fn fold_builtin_leaves_modulo_by_zero_unfolded() {
+        let builtin = mir::Builtin::ModUnsigned(
+            Atom::Literal(mir::Literal::Integer(10, mir::IntegerKind::U32)),
+            Atom::Literal(mir::Literal::Integer(0, mir::IntegerKind::U32)),
+        );
+
+        let result = fold_builtin(builtin);
+
+        assert!(
+            matches!(result, Ast::Builtin(mir::Builtin::ModUnsigned(..))),
+            "modulo by a literal zero must stay unfolded so the runtime trap still fires, got {result:?}"
+        );
+    }
+
+    #[test]
+    fn fold_builtin_wraps_overflowing_add_at_the_operations_bit_width() {
+        // 200u8 + 100u8 overflows an 8-bit width and must wrap (300 - 256 = 44), not be computed
+        // as though the operands were some wider integer type.
+        let builtin = mir::Builtin::AddInt(
+            Atom::Literal(mir::Literal::Integer(200, mir::IntegerKind::U8)),
+            Atom::Literal(mir::Literal::Integer(100, mir::IntegerKind::U8)),
+        );
+
+        let result = fold_builtin(builtin);
+
+        match result {
+            Ast::Atom(Atom::Literal(mir::Literal::Integer(value, mir::IntegerKind::U8))) => assert_eq!(value, 44),
+            other => panic!("expected a folded, wrapped 8-bit integer literal, got {other:?}"),
+        }
+    }
+
+    // Regression test for the saturating-vs-wrapping bug in float-to-int folding: a float outside
+    // the target kind's own range must saturate the same way a native `as` cast would, not wrap
+    // through a wider fixed-size intermediate.
+    #[test]
+    fn float_to_signed_saturates_instead_of_wrapping() {
+        assert_eq!(float_to_signed(1000.0, mir::IntegerKind::I8), 127i8 as u8 as u64);
+    }
+
+    #[test]
+    fn float_to_unsigned_saturates_instead_of_wrapping() {
+        assert_eq!(float_to_unsigned(1000.0, mir::IntegerKind::U8), 255u64);
+    }
+
+    // Regression test for the capture scenario `mir::Lambda::evaluate`'s alpha-renaming exists
+    // to prevent: a pending substitution (`x := y`) whose value's id deliberately collides with
+    // the id of a lambda argument bound further in. Without renaming the argument, `y` would
+    // silently become indistinguishable from the lambda's own bound `y` once this lambda is
+    // later applied, since both share the same `DefinitionId`.
+    #[test]
+    fn lambda_renames_arg_on_id_collision_with_pending_substitution() {
+        // substitutions: { x(1) -> y(2) }, lambda: fn(y(2)) -> x(1)
+        let context = context_with_substitution(1, variable_atom(2));
+        let lambda = mir::Lambda { args: vec![variable(2)], body: Box::new(Ast::Atom(variable_atom(1))), compile_time: false };
+
+        let result = lambda.evaluate(&context);
+
+        match result {
+            Atom::Lambda(lambda) => {
+                assert_ne!(lambda.args[0].definition_id, DefinitionId(2), "colliding arg id must be alpha-renamed");
+                // The body still refers to the *outer* y(2) the substitution pointed at, not the
+                // (now renamed) argument, so the reference is unambiguously free of capture.
+                match *lambda.body {
+                    Ast::Atom(Atom::Variable(variable)) => assert_eq!(variable.definition_id, DefinitionId(2)),
+                    other => panic!("expected a residual reference to the outer variable, got {other:?}"),
+                }
+            },
+            other => panic!("expected Atom::Lambda, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn lambda_does_not_rename_arg_without_id_collision() {
+        // substitutions: { x(1) -> y(99) }, lambda: fn(z(2)) -> z(2)
+        let context = context_with_substitution(1, variable_atom(99));
+        let lambda = mir::Lambda { args: vec![variable(2)], body: Box::new(Ast::Atom(variable_atom(2))), compile_time: false };
+
+        let result = lambda.evaluate(&context);
+
+        match result {
+            Atom::Lambda(lambda) => assert_eq!(lambda.args[0].definition_id, DefinitionId(2), "no collision, no rename needed"),
+            other => panic!("expected Atom::Lambda, got {other:?}"),
+        }
+    }
+
+    // Same collision scenario as `lambda_renames_arg_on_id_collision_with_pending_substitution`,
+    // but for `DecisionTree::Let`, which chunk0-4 left unprotected even though it shadows
+    // substitutions the same way a lambda's args do.
+    #[test]
+    fn decision_tree_let_renames_binding_on_id_collision() {
+        // substitutions: { x(1) -> y(2) }, tree: let y(2) = <anything> in x(1)
+        let context = context_with_substitution(1, variable_atom(2));
+        let leaf = mir::DecisionTree::Leaf(mir::DecisionTreeLeaf { bindings: vec![variable_atom(1)] });
+        let tree = decision_tree_let(2, variable_atom(42), leaf);
+
+        let result = evaluate_decision_tree(tree, &context);
+
+        match result {
+            mir::DecisionTree::Let(let_) => {
+                assert_ne!(let_.variable_to_bind, DefinitionId(2), "colliding bound id must be alpha-renamed");
+                match *let_.body {
+                    mir::DecisionTree::Leaf(leaf) => match &leaf.bindings[0] {
+                        Atom::Variable(variable) => assert_eq!(variable.definition_id, DefinitionId(2)),
+                        other => panic!("expected a residual reference to the outer variable, got {other:?}"),
+                    },
+                    other => panic!("expected a Leaf, got {other:?}"),
+                }
+            },
+            other => panic!("expected DecisionTree::Let, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rename_in_ast_stops_at_rebinding_let() {
+        // let y(2) = y(2) in y(2)   -- renaming y(2) -> fresh(3) must rename the outer `y(2)` in
+        // the expr but not the body's `y(2)`, since that `y` is bound by this very `Let` and refers
+        // to its own binding, not the one being renamed.
+        let ast = Ast::Let(mir::Let {
+            variable: DefinitionId(2),
+            expr: Box::new(Ast::Atom(variable_atom(2))),
+            body: Box::new(Ast::Atom(variable_atom(2))),
+        });
+
+        let renamed = rename_in_ast(ast, DefinitionId(2), DefinitionId(3));
+
+        match renamed {
+            Ast::Let(let_) => {
+                match *let_.expr {
+                    Ast::Atom(Atom::Variable(variable)) => assert_eq!(variable.definition_id, DefinitionId(3)),
+                    other => panic!("expected the renamed expr, got {other:?}"),
+                }
+                match *let_.body {
+                    Ast::Atom(Atom::Variable(variable)) => assert_eq!(variable.definition_id, DefinitionId(2)),
+                    other => panic!("expected the untouched body, got {other:?}"),
+                }
+            },
+            other => panic!("expected Ast::Let, got {other:?}"),
+        }
+    }
+}